@@ -1,13 +1,21 @@
 use libc::c_uint;
 use nix::sys::socket::sockopt::UdpGroSegment;
-use nix::sys::socket::ControlMessageOwned::UdpGroSegments;
-use nix::sys::socket::{recvmsg, setsockopt, AddressFamily, MsgFlags, SockaddrLike, SockaddrStorage};
+use nix::sys::socket::{setsockopt, AddressFamily, MsgFlags, SockaddrLike, SockaddrStorage};
+use quiche_endpoint::MAX_UDP_PAYLOAD;
 use std::io;
-use std::io::IoSliceMut;
 use std::mem::size_of;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::os::fd::AsRawFd;
 
+/// Default number of datagrams fetched per `RecvBatch::recv` call.
+pub const DEFAULT_BATCH_SIZE: usize = 64;
+
+/// Bytes of control-message buffer kept around per datagram: room for one
+/// `UDP_GRO` cmsg (`u32`) plus one `IP_TOS`/`IPV6_TCLASS` cmsg (`c_int`), i.e.
+/// `CMSG_SPACE(4) * 2` (48 bytes on x86_64 Linux) rounded up for headroom.
+#[cfg(target_os = "linux")]
+pub(crate) const RECV_CMSG_LEN: usize = 64;
+
 
 /// For Linux, try to detect GRO is available.
 #[cfg(target_os = "linux")]
@@ -17,57 +25,283 @@ pub fn enable_gro(socket: &mio::net::UdpSocket) -> bool {
     setsockopt(&fd, UdpGroSegment, &true).is_ok()
 }
 
-// Receive packet using recvmsg() with GRO
+/// Request that the kernel report the TOS/traffic-class byte (and with it the
+/// ECN field) as a control message on every received datagram, via
+/// `IP_RECVTOS` / `IPV6_RECVTCLASS`. There's no dedicated `nix` sockopt type
+/// for these, so fall back to a raw `setsockopt(2)`, same as `set_txtime_sockopt`.
+///
+/// Only wired up on Linux, since that's the only platform `recv_from_gro` and
+/// the `recvmmsg`-based `RecvBatch` (the receive paths that can actually decode
+/// the resulting cmsg) run on.
+#[cfg(target_os = "linux")]
+pub fn enable_ecn(socket: &mio::net::UdpSocket, local_addr: SocketAddr) -> bool {
+    let (level, name) = match local_addr {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_RECVTOS),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS),
+    };
+    let enable: libc::c_int = 1;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enable as *const _ as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    ret == 0
+}
+
+/// Walk a received `msghdr`'s control messages for the `UDP_GRO` segment size
+/// and the ECN field carried in an `IP_TOS`/`IPV6_TCLASS` cmsg.
+///
+/// This walks the raw buffer with the libc `CMSG_*` macros instead of going
+/// through nix's `cmsgs()`: nix has no typed variant for `IP_TOS`/`IPV6_TCLASS`,
+/// and its catch-all `ControlMessageOwned::Unknown` carries its header/data in
+/// fields that aren't public in any nix version, so there's no safe way to
+/// read them back out through nix's own iterator.
+#[cfg(target_os = "linux")]
+unsafe fn read_cmsgs(mhdr: &libc::msghdr, gro_size: &mut u16, ecn: &mut u8) {
+    let mut cmsg = libc::CMSG_FIRSTHDR(mhdr);
+    while !cmsg.is_null() {
+        let hdr = &*cmsg;
+        let data = libc::CMSG_DATA(cmsg);
+        if hdr.cmsg_level == libc::SOL_UDP && hdr.cmsg_type == libc::UDP_GRO {
+            *gro_size = std::ptr::read_unaligned(data as *const u32) as u16;
+        } else if (hdr.cmsg_level == libc::IPPROTO_IP && hdr.cmsg_type == libc::IP_TOS)
+            || (hdr.cmsg_level == libc::IPPROTO_IPV6 && hdr.cmsg_type == libc::IPV6_TCLASS)
+        {
+            *ecn = std::ptr::read_unaligned(data as *const libc::c_int) as u8 & 0b11;
+        }
+        cmsg = libc::CMSG_NXTHDR(mhdr, cmsg);
+    }
+}
+
+// Receive packet using recvmsg() with GRO and/or ECN control messages.
 #[cfg(target_os = "linux")]
 fn recv_from_gro(
     socket: &mio::net::UdpSocket,
     buf: &mut [u8],
     cmsg_buf: &mut Vec<u8>,
     flags: MsgFlags,
-) -> io::Result<(usize, SocketAddr, u16)> {
-    unsafe { debug_assert!(cmsg_buf.capacity() >= libc::CMSG_SPACE(size_of::<u32>() as c_uint) as usize); }
-
-    let mut iov = [IoSliceMut::new(buf)];
-    let sockfd = socket.as_raw_fd();
-
-    match recvmsg::<SockaddrStorage>(
-        sockfd,
-        &mut iov,
-        Some(cmsg_buf),
-        flags,
-    ) {
-        Ok(msg) => {
-            let mut gro_size = 0;
-            for cmsg in msg.cmsgs()? {
-                match cmsg {
-                    UdpGroSegments(s) => gro_size = s,
-                    _ => panic!("unexpected control message")
-                }
-            }
-            let addr = msg.address.map(|a| match a.family()? {
-                AddressFamily::Inet => a.as_sockaddr_in().map(|a| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(a.ip()), a.port()))),
-                AddressFamily::Inet6 => a.as_sockaddr_in6().map(|a| SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(a.ip()), a.port(), a.flowinfo(), a.scope_id()))),
-                _ => unreachable!()
-            }).flatten().unwrap();
+) -> io::Result<(usize, SocketAddr, u16, u8)> {
+    debug_assert!(cmsg_buf.capacity() >= RECV_CMSG_LEN);
 
-            Ok((msg.bytes, addr, gro_size as u16))
-        }
-        Err(e) => Err(e.into())
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+    let mut name: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+
+    let mut mhdr = libc::msghdr {
+        msg_name: &mut name as *mut _ as *mut libc::c_void,
+        msg_namelen: size_of::<libc::sockaddr_storage>() as u32,
+        msg_iov: &mut iov,
+        msg_iovlen: 1,
+        msg_control: cmsg_buf.as_mut_ptr() as *mut libc::c_void,
+        msg_controllen: cmsg_buf.capacity(),
+        msg_flags: 0,
+    };
+
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut mhdr, flags.bits()) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
     }
+
+    let (mut gro_size, mut ecn) = (0u16, 0u8);
+    unsafe { read_cmsgs(&mhdr, &mut gro_size, &mut ecn); }
+
+    let addr = unsafe {
+        SockaddrStorage::from_raw(&name as *const _ as *const libc::sockaddr, Some(mhdr.msg_namelen))
+    };
+
+    Ok((n as usize, to_std_addr(addr), gro_size, ecn))
 }
 
+fn to_std_addr(addr: Option<SockaddrStorage>) -> SocketAddr {
+    addr.map(|a| match a.family()? {
+        AddressFamily::Inet => a.as_sockaddr_in().map(|a| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(a.ip()), a.port()))),
+        AddressFamily::Inet6 => a.as_sockaddr_in6().map(|a| SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(a.ip()), a.port(), a.flowinfo(), a.scope_id()))),
+        _ => unreachable!()
+    }).flatten().unwrap()
+}
 
+#[cfg(target_os = "linux")]
 pub fn recv_from(
     socket: &mio::net::UdpSocket,
     buf: &mut [u8],
     cmsg_buf: &mut Vec<u8>,
     flags: MsgFlags,
     enable_gro: bool,
-) -> io::Result<(usize, SocketAddr, u16)> {
-    if enable_gro {
+    enable_ecn: bool,
+) -> io::Result<(usize, SocketAddr, u16, u8)> {
+    if enable_gro || enable_ecn {
         recv_from_gro(socket, buf, cmsg_buf, flags)
     } else {
-        socket.recv_from(buf).map(|(size, addr)| (size, addr, size as u16))
+        socket.recv_from(buf).map(|(size, addr)| (size, addr, size as u16, 0))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv_from(
+    socket: &mio::net::UdpSocket,
+    buf: &mut [u8],
+    _cmsg_buf: &mut Vec<u8>,
+    _flags: MsgFlags,
+    _enable_gro: bool,
+    _enable_ecn: bool,
+) -> io::Result<(usize, SocketAddr, u16, u8)> {
+    socket.recv_from(buf).map(|(size, addr)| (size, addr, size as u16, 0))
+}
+
+// Batched receive built on `recvmmsg`: fills up to `N` message slots in a
+// single syscall instead of one `recvmsg` per datagram.
+//
+// Built on the raw libc `recvmmsg` rather than nix's `MultiHeaders` wrapper:
+// the per-message control buffer nix manages internally is only reachable
+// through its `cmsgs()` iterator, which (like `recv_from_gro`'s problem)
+// can't read the ECN cmsg. Owning the `mmsghdr`/`iovec`/control buffers
+// ourselves lets `read_cmsgs` walk them directly.
+#[cfg(target_os = "linux")]
+pub struct RecvBatch {
+    bufs: Vec<[u8; MAX_UDP_PAYLOAD]>,
+    cmsg_bufs: Vec<[u8; RECV_CMSG_LEN]>,
+    names: Vec<libc::sockaddr_storage>,
+    // Raw iovec/mmsghdr arrays borrowing the buffers above; see the safety
+    // comment in `Self::new` for why it's sound to build these once and
+    // reuse them across calls instead of rebuilding them (and reallocating
+    // their backing storage) on every `Self::recv` call.
+    iovs: Vec<libc::iovec>,
+    msgs: Vec<libc::mmsghdr>,
+    results: Vec<(usize, SocketAddr, u16, u8)>,
+}
+
+#[cfg(target_os = "linux")]
+impl RecvBatch {
+    pub fn new(batch_size: usize) -> Self {
+        debug_assert!(RECV_CMSG_LEN >= unsafe {
+            libc::CMSG_SPACE(size_of::<u32>() as c_uint) as usize
+                + libc::CMSG_SPACE(size_of::<libc::c_int>() as c_uint) as usize
+        });
+
+        let mut bufs = vec![[0u8; MAX_UDP_PAYLOAD]; batch_size];
+        let mut cmsg_bufs = vec![[0u8; RECV_CMSG_LEN]; batch_size];
+        let mut names = vec![unsafe { std::mem::zeroed::<libc::sockaddr_storage>() }; batch_size];
+
+        let iovs: Vec<libc::iovec> = bufs.iter_mut()
+            .map(|buf| libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() })
+            .collect();
+
+        let names_ptr = names.as_mut_ptr();
+        let cmsg_ptr = cmsg_bufs.as_mut_ptr();
+        let iovs_ptr = iovs.as_ptr();
+
+        // SAFETY: `names`, `cmsg_bufs` and `iovs` are never resized after this
+        // point, so their backing allocations (and the pointers into them
+        // stashed in `msgs` below) stay valid for as long as `Self` lives.
+        let msgs = (0..batch_size)
+            .map(|i| libc::mmsghdr {
+                msg_hdr: libc::msghdr {
+                    msg_name: unsafe { names_ptr.add(i) } as *mut libc::c_void,
+                    msg_namelen: size_of::<libc::sockaddr_storage>() as u32,
+                    msg_iov: unsafe { iovs_ptr.add(i) } as *mut libc::iovec,
+                    msg_iovlen: 1,
+                    msg_control: unsafe { cmsg_ptr.add(i) } as *mut libc::c_void,
+                    msg_controllen: RECV_CMSG_LEN,
+                    msg_flags: 0,
+                },
+                msg_len: 0,
+            })
+            .collect();
+
+        Self {
+            bufs,
+            cmsg_bufs,
+            names,
+            iovs,
+            msgs,
+            results: Vec::with_capacity(batch_size),
+        }
+    }
+
+    /// Receive a batch of datagrams with a single `recvmmsg` syscall.
+    /// Returns the number of datagrams received; use `Self::entry_mut` to
+    /// get at each one's payload, source address, GRO segment size and ECN field.
+    pub fn recv(&mut self, socket: &mio::net::UdpSocket, flags: MsgFlags) -> io::Result<usize> {
+        // The kernel shrinks msg_namelen/msg_controllen to the actual
+        // received size on each call; reset them before reusing the slots.
+        for msg in self.msgs.iter_mut() {
+            msg.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as u32;
+            msg.msg_hdr.msg_controllen = RECV_CMSG_LEN;
+        }
+
+        let n = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                self.msgs.as_mut_ptr(),
+                self.msgs.len() as c_uint,
+                flags.bits(),
+                std::ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let n = n as usize;
+
+        self.results.clear();
+        for i in 0..n {
+            let (mut gro_size, mut ecn) = (0u16, 0u8);
+            unsafe { read_cmsgs(&self.msgs[i].msg_hdr, &mut gro_size, &mut ecn); }
+
+            let addr = unsafe {
+                SockaddrStorage::from_raw(&self.names[i] as *const _ as *const libc::sockaddr, Some(self.msgs[i].msg_hdr.msg_namelen))
+            };
+
+            self.results.push((self.msgs[i].msg_len as usize, to_std_addr(addr), gro_size, ecn));
+        }
+        Ok(n)
+    }
+
+    /// Get the `i`-th received datagram: its payload (truncated to the
+    /// received length), source address, GRO segment size and ECN field.
+    pub fn entry_mut(&mut self, i: usize) -> (&mut [u8], SocketAddr, u16, u8) {
+        let (len, from, gro_size, ecn) = self.results[i];
+        (&mut self.bufs[i][..len], from, gro_size, ecn)
     }
 }
 
+/// Fallback for platforms without `recvmmsg`: a loop of single-datagram
+/// `recv_from` calls behind the same batch API.
+#[cfg(not(target_os = "linux"))]
+pub struct RecvBatch {
+    bufs: Vec<[u8; MAX_UDP_PAYLOAD]>,
+    results: Vec<(usize, SocketAddr, u16, u8)>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl RecvBatch {
+    pub fn new(batch_size: usize) -> Self {
+        Self {
+            bufs: vec![[0u8; MAX_UDP_PAYLOAD]; batch_size],
+            results: Vec::with_capacity(batch_size),
+        }
+    }
+
+    pub fn recv(&mut self, socket: &mio::net::UdpSocket, flags: MsgFlags) -> io::Result<usize> {
+        self.results.clear();
+        for buf in self.bufs.iter_mut() {
+            match socket.recv_from(buf.as_mut_slice()) {
+                // the std socket gives us no way to read the TOS/traffic-class cmsg, so ECN is unavailable here.
+                Ok((size, addr)) => self.results.push((size, addr, size as u16, 0)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && !self.results.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.results.len())
+    }
+
+    pub fn entry_mut(&mut self, i: usize) -> (&mut [u8], SocketAddr, u16, u8) {
+        let (len, from, gro_size, ecn) = self.results[i];
+        (&mut self.bufs[i][..len], from, gro_size, ecn)
+    }
+}
@@ -9,6 +9,9 @@ use quiche_endpoint::{quiche, Endpoint};
 use slab::Slab;
 use std::cmp::min;
 use std::io;
+use std::ops::ControlFlow;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{mpsc, Arc};
 use std::time::Duration;
 
 /// Runner handles socket IO and the run loop for an `Endpoint`, which multiplexes client and server QUIC connections.
@@ -21,6 +24,7 @@ pub struct Runner<TConnAppData, TAppData, TExternalEventValue> {
     pub endpoint: Endpoint<TConnAppData, TAppData>,
     pub registry: Registry<TExternalEventValue>,
     app_timeout: Option<Duration>,
+    external_event_rx: mpsc::Receiver<TExternalEventValue>,
 }
 
 impl<'a, TConnAppData, TAppData, TExternalEventValue> Runner<TConnAppData, TAppData, TExternalEventValue> {
@@ -34,6 +38,11 @@ impl<'a, TConnAppData, TAppData, TExternalEventValue> Runner<TConnAppData, TAppD
             let token = events.insert(Event::Close);
             poll.registry().register(close_pipe_rx, mio::Token(token), mio::Interest::READABLE).unwrap();
         }
+
+        let waker_token = events.insert(Event::Waker);
+        let waker = Arc::new(mio::Waker::new(poll.registry(), mio::Token(waker_token)).unwrap());
+        let (external_event_tx, external_event_rx) = mpsc::channel();
+
         Self {
             config,
             buf: [0; MAX_UDP_PAYLOAD],
@@ -43,8 +52,11 @@ impl<'a, TConnAppData, TAppData, TExternalEventValue> Runner<TConnAppData, TAppD
             registry: Registry {
                 events,
                 poll,
+                external_event_tx,
+                waker,
             },
             app_timeout: None,
+            external_event_rx,
         }
     }
 
@@ -66,79 +78,120 @@ impl<'a, TConnAppData, TAppData, TExternalEventValue> Runner<TConnAppData, TAppD
     /// this function return when all connections are closed `Self::server` is `None`.
     /// if `Self::server` is `Some` the function will never return.
     pub fn run(&mut self) {
-        'run: loop {
-            let timeout = match (self.endpoint.has_pending_sends(), self.endpoint.timeout(), self.app_timeout.take()) {
-                (true, _, _) => Some(Duration::from_secs(0)),
-                (false, Some(d), None) => Some(d),
-                (false, None, Some(d)) => Some(d),
-                (false, Some(quic_timeout), Some(app_timeout)) => Some(min(quic_timeout, app_timeout)),
-                (false, None, None) => None,
-            };
-
-            trace!("poll with timeout {:?}", timeout);
-            let mut poll_res = self.registry.poll.poll(&mut self.mio_events, timeout);
-            while let Err(e) = poll_res.as_ref() {
-                if e.kind() == io::ErrorKind::Interrupted {
-                    trace!("mio poll() call failed, retrying: {:?}", e);
-                    poll_res = self.registry.poll.poll(&mut self.mio_events, timeout);
-                } else {
-                    panic!("mio poll() call failed fatally: {:?}", e);
-                }
+        loop {
+            if let ControlFlow::Break(()) = self.run_once() {
+                break;
             }
+        }
+    }
 
-            (self.config.pre_handle_recvs)(self);
+    /// the timeout that the next call to `Self::run_once` should block for,
+    /// i.e. the minimum of the pending QUIC timeout and the app timeout set via
+    /// `Self::set_app_timeout`, or zero while the endpoint has pending sends.
+    /// `None` means block indefinitely.
+    ///
+    /// an external async executor driving the runner via `Self::run_once` can use this
+    /// to arm its own timer alongside polling `Self::as_raw_fd` for readability.
+    pub fn timeout(&self) -> Option<Duration> {
+        match (self.endpoint.has_pending_sends(), self.endpoint.timeout(), self.app_timeout) {
+            (true, _, _) => Some(Duration::from_secs(0)),
+            (false, Some(d), None) => Some(d),
+            (false, None, Some(d)) => Some(d),
+            (false, Some(quic_timeout), Some(app_timeout)) => Some(min(quic_timeout, app_timeout)),
+            (false, None, None) => None,
+        }
+    }
+
+    /// run a single iteration of the protocol loop: poll for IO, drain events, drive the
+    /// endpoint and flush outgoing packets.
+    ///
+    /// unlike `Self::run` this does not block the calling thread in a loop, so it can be
+    /// driven cooperatively by an external async executor: register `Self::as_raw_fd` with
+    /// the executor's reactor and call `run_once` whenever the fd becomes readable, or after
+    /// `Self::timeout` elapses.
+    ///
+    /// returns `ControlFlow::Break` once the runner should stop being driven, i.e. when all
+    /// client connections are closed, or the close pipe fired.
+    pub fn run_once(&mut self) -> ControlFlow<()> {
+        let timeout = self.timeout();
+        self.app_timeout = None;
 
-            if self.mio_events.is_empty() && !self.endpoint.has_pending_sends() {
-                self.endpoint.on_timeout();
+        trace!("poll with timeout {:?}", timeout);
+        let mut poll_res = self.registry.poll.poll(&mut self.mio_events, timeout);
+        while let Err(e) = poll_res.as_ref() {
+            if e.kind() == io::ErrorKind::Interrupted {
+                trace!("mio poll() call failed, retrying: {:?}", e);
+                poll_res = self.registry.poll.poll(&mut self.mio_events, timeout);
             } else {
-                for mio_event in &self.mio_events {
-                    let event = self.registry.events.get(mio_event.token().into()).unwrap();
-                    let r = Self::handle_event(
-                        mio_event,
-                        event,
-                        &mut self.sockets.sockets,
-                        self.buf.as_mut(),
-                        &mut self.endpoint,
-                        self.config.on_external_event,
-                    );
-                    match r {
-                        Err(endpoint::Error::CloseByUser) => {
-                            break 'run
-                        },
-                        Err(e) => { panic!("{:?}", e)},
-                        Ok(()) => {},
-                    }
-                }
+                panic!("mio poll() call failed fatally: {:?}", e);
             }
+        }
 
-            (self.config.post_handle_recvs)(self);
+        (self.config.pre_handle_recvs)(self);
 
-            // send as long as packets are available
-            loop {
-                let ok = match self.endpoint.send_packets_out(&mut self.buf) {
-                    Ok(v) => v,
-                    Err(quiche_endpoint::Error::Quiche(quiche::Error::Done)) => break,
-                    Err(e) => panic!("unexpected error: {:?}", e),
-                };
-                match self.sockets.send(&self.buf[..ok.total], &ok.send_info, ok.segment_size) {
-                    Ok(_) => {}
-                    Err(e) => error!("error sending UDP datagram: {:?}", e),
+        if self.mio_events.is_empty() && !self.endpoint.has_pending_sends() {
+            self.endpoint.on_timeout();
+        } else {
+            for mio_event in &self.mio_events {
+                let event = self.registry.events.get(mio_event.token().into()).unwrap();
+
+                // external events pushed via `Registry::external_event_handle` are drained
+                // here rather than going through `Self::handle_event`, since dispatching
+                // them needs the queue receiver, which only the runner owns.
+                if matches!(event, Event::Waker) {
+                    while let Ok(value) = self.external_event_rx.try_recv() {
+                        if let Some(on_external_event) = self.config.on_external_event {
+                            on_external_event(&mut self.endpoint, &value);
+                        }
+                    }
+                    continue;
+                }
+
+                let r = Self::handle_event(
+                    mio_event,
+                    event,
+                    &mut self.sockets.sockets,
+                    &mut self.endpoint,
+                    self.config.on_external_event,
+                );
+                match r {
+                    Err(endpoint::Error::CloseByUser) => {
+                        return ControlFlow::Break(())
+                    },
+                    Err(e) => { panic!("{:?}", e)},
+                    Ok(()) => {},
                 }
             }
+        }
 
-            self.endpoint.collect_garbage();
+        (self.config.post_handle_recvs)(self);
 
-            if !self.endpoint.is_server() && self.endpoint.num_conns() == 0 {
-                break; // stop because all client connections are closed
+        // send as long as packets are available
+        loop {
+            let ok = match self.endpoint.send_packets_out(&mut self.buf) {
+                Ok(v) => v,
+                Err(quiche_endpoint::Error::Quiche(quiche::Error::Done)) => break,
+                Err(e) => panic!("unexpected error: {:?}", e),
+            };
+            match self.sockets.send(&self.buf[..ok.total], &ok.send_info, ok.segment_size) {
+                Ok(_) => {}
+                Err(e) => error!("error sending UDP datagram: {:?}", e),
             }
         }
+
+        self.endpoint.collect_garbage();
+
+        if !self.endpoint.is_server() && self.endpoint.num_conns() == 0 {
+            return ControlFlow::Break(()); // stop because all client connections are closed
+        }
+
+        ControlFlow::Continue(())
     }
 
     pub fn handle_event(
         mio_event: &mio::event::Event,
         event: &Event<TExternalEventValue>,
         sockets: &mut Slab<Socket>,
-        buf: &mut [u8],
         endpoint: &mut Endpoint<TConnAppData, TAppData>,
         on_external_event: Option<fn(&mut Endpoint<TConnAppData, TAppData>, &TExternalEventValue)>,
     ) -> endpoint::Result<()>  {
@@ -147,7 +200,7 @@ impl<'a, TConnAppData, TAppData, TExternalEventValue> Runner<TConnAppData, TAppD
                 Err(endpoint::Error::CloseByUser)
             }
             Event::Socket(_) => {
-                Self::handle_readable_event(mio_event, event, sockets, buf, endpoint)
+                Self::handle_readable_event(mio_event, event, sockets, endpoint)
             }
             Event::External(v) => {
                 if let Some(on_external_event) = on_external_event {
@@ -155,6 +208,9 @@ impl<'a, TConnAppData, TAppData, TExternalEventValue> Runner<TConnAppData, TAppD
                 }
                 Ok(())
             }
+            Event::Waker => {
+                unreachable!("Event::Waker is drained directly in Runner::run_once")
+            }
         }
     }
 
@@ -162,7 +218,6 @@ impl<'a, TConnAppData, TAppData, TExternalEventValue> Runner<TConnAppData, TAppD
         mio_event: &mio::event::Event,
         event: &Event<TExternalEventValue>,
         sockets: &mut Slab<Socket>,
-        buf: &mut [u8],
         endpoint: &mut Endpoint<TConnAppData, TAppData>,
     ) -> endpoint::Result<()> {
         debug_assert!(mio_event.is_readable());
@@ -173,14 +228,14 @@ impl<'a, TConnAppData, TAppData, TExternalEventValue> Runner<TConnAppData, TAppD
         };
         let local_addr = socket.local_addr;
         'read: loop {
-            let (len, from, segment_size) = match socket.recv(buf) {
+            let n = match socket.recv_batch() {
                 Ok(v) => v,
 
                 Err(e) => {
                     // There are no more UDP packets to read on this socket.
                     // Process subsequent events.
                     if e.kind() == std::io::ErrorKind::WouldBlock {
-                        trace!("{}: recv() would block", local_addr);
+                        trace!("{}: recv_batch() would block", local_addr);
                         break 'read;
                     }
 
@@ -188,52 +243,61 @@ impl<'a, TConnAppData, TAppData, TExternalEventValue> Runner<TConnAppData, TAppD
                 }
             };
 
-            let segment_size = if segment_size == 0 {
-                len
-            } else {
-                segment_size as usize
-            };
+            if n == 0 {
+                break 'read;
+            }
 
-            trace!("{}: got {} bytes of {} byte segments", local_addr, len, segment_size);
+            for i in 0..n {
+                let (superpacket, from, segment_size, ecn) = socket.recv_batch_entry(i);
 
-            let info = quiche::RecvInfo {
-                to: local_addr,
-                from,
-            };
+                let segment_size = if segment_size == 0 {
+                    superpacket.len()
+                } else {
+                    segment_size as usize
+                };
 
-            // process GRO segments
-            // if disabled just process the one
-            for segment in buf[..len].chunks_mut(segment_size) {
-                match endpoint.recv(segment, info) {
-                    Ok(_) => {} // everything ok
-                    Err(endpoint::Error::InvalidHeader(e)) => {
-                        error!("Parsing packet header failed: {:?}", e);
-                        continue;
-                    }
-                    Err(endpoint::Error::UnknownConnID) => {
-                        debug!("Received unknown connection id packet");
-                        continue;
-                    }
-                    Err(endpoint::Error::IO(e)) => {
-                        if e.kind() == io::ErrorKind::WouldBlock {
-                            trace!("send() would block");
-                            break;
+                trace!("{}: got {} bytes of {} byte segments, ecn={}", local_addr, superpacket.len(), segment_size, ecn);
+
+                let info = quiche::RecvInfo {
+                    to: local_addr,
+                    from,
+                    ecn,
+                };
+
+                // process GRO segments
+                // if disabled just process the one
+                for segment in superpacket.chunks_mut(segment_size) {
+                    match endpoint.recv(segment, info) {
+                        Ok(_) => {} // everything ok
+                        Err(endpoint::Error::InvalidHeader(e)) => {
+                            error!("Parsing packet header failed: {:?}", e);
+                            continue;
                         }
+                        Err(endpoint::Error::UnknownConnID) => {
+                            debug!("Received unknown connection id packet");
+                            continue;
+                        }
+                        Err(endpoint::Error::IO(e)) => {
+                            if e.kind() == io::ErrorKind::WouldBlock {
+                                trace!("send() would block");
+                                break;
+                            }
 
-                        panic!("send() failed: {:?}", e);
-                    }
-                    Err(endpoint::Error::InvalidAddrToken) => {
-                        continue
-                    }
-                    Err(endpoint::Error::InvalidConnID) => {
-                        continue
-                    }
-                    Err(endpoint::Error::QuicheRecvFailed(e)) => {
-                        error!("{}: quiche recv failed: {:?}", local_addr, e);
-                        continue
-                    }
-                    e => {
-                        panic!("unexpected error: {:?}", e)
+                            panic!("send() failed: {:?}", e);
+                        }
+                        Err(endpoint::Error::InvalidAddrToken) => {
+                            continue
+                        }
+                        Err(endpoint::Error::InvalidConnID) => {
+                            continue
+                        }
+                        Err(endpoint::Error::QuicheRecvFailed(e)) => {
+                            error!("{}: quiche recv failed: {:?}", local_addr, e);
+                            continue
+                        }
+                        e => {
+                            panic!("unexpected error: {:?}", e)
+                        }
                     }
                 }
             }
@@ -252,9 +316,19 @@ impl<'a, TConnAppData, TAppData, TExternalEventValue> Runner<TConnAppData, TAppD
     }
 }
 
+impl<TConnAppData, TAppData, TExternalEventValue> AsRawFd for Runner<TConnAppData, TAppData, TExternalEventValue> {
+    /// the fd of the underlying `mio::Poll` instance, for registering the runner with an
+    /// external async executor's reactor; see `Self::run_once`.
+    fn as_raw_fd(&self) -> RawFd {
+        self.registry.poll.as_raw_fd()
+    }
+}
+
 pub struct Registry<TExternalEventValue> {
     events: Slab<Event<TExternalEventValue>>,
     poll: mio::Poll,
+    external_event_tx: mpsc::Sender<TExternalEventValue>,
+    waker: Arc<mio::Waker>,
 }
 
 impl <TExternalEventValue> Registry<TExternalEventValue> {
@@ -273,10 +347,47 @@ impl <TExternalEventValue> Registry<TExternalEventValue> {
             interest,
         ).unwrap();
     }
+
+    /// Get a clonable handle that lets any thread enqueue a `TExternalEventValue`
+    /// into the run loop, without building a dedicated `mio::event::Source` for it.
+    /// Values enqueued this way are dispatched through `Config::on_external_event`.
+    pub fn external_event_handle(&self) -> ExternalEventHandle<TExternalEventValue> {
+        ExternalEventHandle {
+            tx: self.external_event_tx.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+}
+
+/// A `Send + Sync` handle that enqueues a `TExternalEventValue` and wakes the
+/// run loop so it gets dispatched on the next iteration. Obtained via
+/// `Registry::external_event_handle`.
+pub struct ExternalEventHandle<TExternalEventValue> {
+    tx: mpsc::Sender<TExternalEventValue>,
+    waker: Arc<mio::Waker>,
+}
+
+impl<TExternalEventValue> Clone for ExternalEventHandle<TExternalEventValue> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+}
+
+impl<TExternalEventValue> ExternalEventHandle<TExternalEventValue> {
+    pub fn send(&self, value: TExternalEventValue) -> io::Result<()> {
+        self.tx
+            .send(value)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "runner has been dropped"))?;
+        self.waker.wake()
+    }
 }
 
 pub enum Event<T> {
     Close,
     Socket(usize),
-    External(T)
+    External(T),
+    Waker,
 }
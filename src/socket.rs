@@ -1,15 +1,14 @@
 use crate::recvfrom;
-use crate::recvfrom::recv_from;
+use crate::recvfrom::{recv_from, RecvBatch, DEFAULT_BATCH_SIZE};
 use crate::sendto::{detect_gso, send_to};
 use libc::{ioctl, TIOCOUTQ};
 use mio::net::UdpSocket;
-use nix::cmsg_space;
-use nix::sys::socket::sockopt::UdpGsoSegment;
-use nix::sys::socket::{setsockopt, MsgFlags};
+use nix::sys::socket::MsgFlags;
 use quiche_endpoint::quiche;
 use std::io;
+use std::mem::{size_of, MaybeUninit};
 use std::net::SocketAddr;
-use std::os::fd::RawFd;
+use std::os::fd::{AsRawFd, RawFd};
 
 pub struct Socket {
     pub inner: UdpSocket,
@@ -20,35 +19,105 @@ pub struct Socket {
     pub enable_gro: bool,
     pub enable_pacing: bool,
     pub enable_gso: bool,
+    /// Receive-side only: turns on ECN codepoint reporting via `Self::recv`/
+    /// `Self::recv_batch`. Outgoing ECT marking on `Self::send` isn't implemented.
+    pub enable_ecn: bool,
+    recv_batch: RecvBatch,
 }
 
 impl Socket {
-    pub fn bind(addr: SocketAddr, disable_gro: bool, disable_pacing: bool, disable_gso: bool) -> io::Result<Self> {
+    /// `disable_ecn` only affects the receive path (see `Socket::enable_ecn`).
+    pub fn bind(addr: SocketAddr, disable_gro: bool, disable_pacing: bool, disable_gso: bool, disable_ecn: bool) -> io::Result<Self> {
         let inner = mio::net::UdpSocket::bind(addr)?;
         let local_addr = inner.local_addr()?;
 
         let enable_gro = !disable_gro && recvfrom::enable_gro(&inner);
         let enable_pacing = !disable_pacing && set_txtime_sockopt(&inner).is_ok();
         let enable_gso = !disable_gso && detect_gso(&inner, 9000);
+        let enable_ecn = !disable_ecn && recvfrom::enable_ecn(&inner, local_addr);
 
         Ok(Self {
             inner,
             local_addr,
-            cmsg_buf: cmsg_space!([u32; 1]),
+            cmsg_buf: Vec::with_capacity(recvfrom::RECV_CMSG_LEN),
             flags: MsgFlags::empty(),
             enable_gro,
             enable_pacing,
             enable_gso,
+            enable_ecn,
+            recv_batch: RecvBatch::new(DEFAULT_BATCH_SIZE),
         })
     }
 
-    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, u16)> {
-        recv_from(&self.inner, buf, &mut self.cmsg_buf, self.flags, self.enable_gro)
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, u16, u8)> {
+        recv_from(&self.inner, buf, &mut self.cmsg_buf, self.flags, self.enable_gro, self.enable_ecn)
+    }
+
+    /// Receive a batch of datagrams with a single `recvmmsg` syscall (falls
+    /// back to a `recv_from` loop on non-Linux platforms). Returns the number
+    /// of datagrams received; use `Self::recv_batch_entry` to access each.
+    pub fn recv_batch(&mut self) -> io::Result<usize> {
+        self.recv_batch.recv(&self.inner, self.flags)
+    }
+
+    /// Get the `i`-th datagram from the most recent `Self::recv_batch` call:
+    /// its payload (which may still contain multiple GRO segments), source
+    /// address, GRO segment size and ECN field.
+    pub fn recv_batch_entry(&mut self, i: usize) -> (&mut [u8], SocketAddr, u16, u8) {
+        self.recv_batch.entry_mut(i)
     }
 
     pub fn send(&self, buf: &[u8], send_info: &quiche::SendInfo, segment_size: usize) -> io::Result<usize> {
         send_to(&self.inner, buf, send_info, segment_size, self.enable_pacing, self.enable_gso)
     }
+
+    /// Generic `getsockopt(2)` wrapper for options without a dedicated probe.
+    pub fn get_socket_option<T>(&self, level: i32, name: i32) -> io::Result<T> {
+        raw_getsockopt(self.inner.as_raw_fd(), level, name)
+    }
+
+    /// Generic `setsockopt(2)` wrapper, the write-side counterpart of `Self::get_socket_option`.
+    pub fn set_socket_option<T>(&self, level: i32, name: i32, value: &T) -> io::Result<()> {
+        raw_setsockopt(self.inner.as_raw_fd(), level, name, value)
+    }
+}
+
+fn raw_getsockopt<T>(fd: RawFd, level: i32, name: i32) -> io::Result<T> {
+    let mut val: MaybeUninit<T> = MaybeUninit::uninit();
+    let mut len = size_of::<T>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            val.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    assert_eq!(len as usize, size_of::<T>(), "unexpected getsockopt() return size");
+
+    Ok(unsafe { val.assume_init() })
+}
+
+fn raw_setsockopt<T>(fd: RawFd, level: i32, name: i32, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
 }
 
 /// Set SO_TXTIME socket option.
@@ -86,5 +155,5 @@ pub fn send_buffer_queued(fd: RawFd) -> io::Result<usize> {
 
 pub fn gso_supported() -> bool {
     let socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
-    setsockopt(&socket, UdpGsoSegment, &1500).is_ok()
+    raw_setsockopt(socket.as_raw_fd(), libc::SOL_UDP, libc::UDP_SEGMENT, &1500i32).is_ok()
 }